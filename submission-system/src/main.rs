@@ -1,15 +1,29 @@
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
-use std::path::PathBuf;
-use std::sync::RwLock;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use actix_web::{http::header, web, App, HttpResponse, HttpServer};
+use actix_web::{http::header, web, App, HttpRequest, HttpResponse, HttpServer};
+use bollard::container::{Config, LogOutput, LogsOptions, RemoveContainerOptions};
+use bollard::image::{BuildImageOptions, RemoveImageOptions};
+use bollard::models::HostConfig;
+use bollard::Docker;
+use futures::StreamExt;
 use git2::build::RepoBuilder;
+use hmac::{Hmac, Mac};
 use listenfd::ListenFd;
+use rusqlite::{params, Connection};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys};
+use rustls::{NoClientAuth, ServerConfig as RustlsServerConfig};
 use serde::Deserialize;
 use serde::Serialize;
+use sha2::Sha256;
 use tempfile::{Builder};
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::BroadcastStream;
 
 const STYLE: &str = include_str!("style.css");
 
@@ -17,6 +31,18 @@ const STYLE: &str = include_str!("style.css");
 struct ConfigFile {
     repos: Vec<RepoSettings>,
     debug: bool,
+    job_timeout_secs: u64,
+    db_path: String,
+    max_concurrent_jobs: usize,
+    tls: Option<TlsConfig>,
+}
+
+/// Certificate/key pair used to serve the board over HTTPS. When absent the server falls
+/// back to the existing `listenfd`/plaintext path.
+#[derive(Serialize, Deserialize, Clone)]
+struct TlsConfig {
+    cert_path: String,
+    key_path: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -25,17 +51,54 @@ struct RepoSettings {
     clone_url: String,
     deploy_token: String,
     deploy_user: String,
+    webhook_secret: String,
+    memory_limit_bytes: i64,
+    nano_cpus: i64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum TestResult {
     Success { test: Output },
     RunError { run: Output },
     TestError { test: Output },
     RunTestError { run: Output, test: Output },
+    Timeout { phase: ExecutionPhase },
 }
 
-#[derive(Debug)]
+/// Which of the two per-submission containers a result or timeout belongs to.
+#[derive(Debug, Copy, Clone)]
+enum ExecutionPhase {
+    Run,
+    Test,
+}
+
+impl Display for ExecutionPhase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExecutionPhase::Run => f.write_str("run.sh"),
+            ExecutionPhase::Test => f.write_str("test.sh"),
+        }
+    }
+}
+
+impl ExecutionPhase {
+    fn as_str(self) -> &'static str {
+        match self {
+            ExecutionPhase::Run => "run",
+            ExecutionPhase::Test => "test",
+        }
+    }
+
+    fn from_str(s: &str) -> ExecutionPhase {
+        match s {
+            "run" => ExecutionPhase::Run,
+            "test" => ExecutionPhase::Test,
+            other => panic!("unknown execution phase {} in database", other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Output {
     stdout: String,
     stderr: String,
@@ -57,61 +120,398 @@ impl Display for TestResult {
     }
 }
 
+/// The persisted lifecycle state of a `jobs` row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JobState {
+    Pending,
+    Running,
+    Success,
+    Error,
+    Timeout,
+    Superseded,
+}
+
+impl JobState {
+    fn as_str(self) -> &'static str {
+        match self {
+            JobState::Pending => "pending",
+            JobState::Running => "running",
+            JobState::Success => "success",
+            JobState::Error => "error",
+            JobState::Timeout => "timeout",
+            JobState::Superseded => "superseded",
+        }
+    }
+
+    fn from_str(s: &str) -> JobState {
+        match s {
+            "pending" => JobState::Pending,
+            "running" => JobState::Running,
+            "success" => JobState::Success,
+            "error" => JobState::Error,
+            "timeout" => JobState::Timeout,
+            "superseded" => JobState::Superseded,
+            other => panic!("unknown job state {} in database", other),
+        }
+    }
+}
+
+/// The in-memory progress of a queued job, tracked by `JobQueue` in addition to (and ahead
+/// of) whatever is currently persisted for it in the `jobs` table.
 #[derive(Debug)]
-struct TestLogEntry {
+enum JobPhase {
+    Queued,
+    Cloning,
+    Building,
+    Running,
+    Testing,
+    Done(TestResult),
+    Failed(String),
+}
+
+impl Display for JobPhase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobPhase::Queued => f.write_str("Queued"),
+            JobPhase::Cloning => f.write_str("Cloning repository"),
+            JobPhase::Building => f.write_str("Building image"),
+            JobPhase::Running => f.write_str("Running run.sh"),
+            JobPhase::Testing => f.write_str("Running test.sh"),
+            JobPhase::Done(_) => f.write_str("Finishing up"),
+            JobPhase::Failed(message) => write!(f, "Failed: {}", message),
+        }
+    }
+}
+
+/// Everything a worker needs to run the pipeline for one queued push, independent of the
+/// webhook request that created it.
+struct JobDescriptor {
+    job_id: i64,
     repository: String,
     branch: String,
-    result: TestLogResult,
+    clone_url: String,
+    memory_limit_bytes: i64,
+    nano_cpus: i64,
 }
 
-#[derive(Debug)]
-enum TestLogResult {
-    Success(Output),
-    SetupError(SetupError),
-    TestError {
-        run_error_log: Option<Output>,
-        test_error_log: Option<Output>,
-    },
+/// A bounded pool of workers pulling `JobDescriptor`s off an unbounded channel, so a burst
+/// of webhooks queues up instead of spawning unbounded concurrent `docker build`/`run`s.
+/// Also tracks each live job's current `JobPhase` and coalesces same repo+branch pushes,
+/// so a newer push supersedes a still-queued older one instead of both running.
+struct JobQueue {
+    sender: mpsc::UnboundedSender<JobDescriptor>,
+    phases: Mutex<HashMap<i64, JobPhase>>,
+    queued_order: Mutex<VecDeque<i64>>,
+    latest_for_key: Mutex<HashMap<(String, String), i64>>,
+}
+
+impl JobQueue {
+    /// Creates the queue and spawns `max_concurrent_jobs` worker tasks that drain it.
+    fn new(
+        max_concurrent_jobs: usize,
+        job_timeout: Duration,
+        docker: web::Data<Docker>,
+        db: web::Data<DbCtx>,
+        broadcasts: web::Data<JobBroadcasts>,
+    ) -> web::Data<JobQueue> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let queue = web::Data::new(JobQueue {
+            sender,
+            phases: Mutex::new(HashMap::new()),
+            queued_order: Mutex::new(VecDeque::new()),
+            latest_for_key: Mutex::new(HashMap::new()),
+        });
+
+        let receiver = Arc::new(tokio::sync::Mutex::new(receiver));
+
+        for worker_id in 0..max_concurrent_jobs {
+            let receiver = receiver.clone();
+            let docker = docker.clone();
+            let db = db.clone();
+            let broadcasts = broadcasts.clone();
+            let queue = queue.clone();
+
+            actix_rt::spawn(async move {
+                loop {
+                    let descriptor = receiver.lock().await.recv().await;
+                    let descriptor = match descriptor {
+                        Some(descriptor) => descriptor,
+                        None => break,
+                    };
+
+                    run_queued_job(&queue, &docker, &db, &broadcasts, descriptor, job_timeout).await;
+                }
+
+                println!("Job queue worker {} shut down", worker_id);
+            });
+        }
+
+        queue
+    }
+
+    /// Queues `descriptor`, superseding any still-queued job for the same repo+branch.
+    ///
+    /// Concurrent webhook handlers can call this out of push order (ids are assigned by
+    /// `db.insert_pending` before `enqueue` is reached, and handlers race from there), so
+    /// `latest_for_key` keeps the highest job id seen rather than simply the most recent
+    /// caller, which would let an older push win a race and wrongly supersede a newer one.
+    fn enqueue(&self, descriptor: JobDescriptor) {
+        let key = (descriptor.repository.clone(), descriptor.branch.clone());
+
+        self.latest_for_key
+            .lock()
+            .unwrap()
+            .entry(key)
+            .and_modify(|latest| *latest = (*latest).max(descriptor.job_id))
+            .or_insert(descriptor.job_id);
+        self.queued_order.lock().unwrap().push_back(descriptor.job_id);
+        self.phases.lock().unwrap().insert(descriptor.job_id, JobPhase::Queued);
+
+        let _ = self.sender.send(descriptor);
+    }
+
+    /// True if a newer push for the same repo+branch has queued since `descriptor` did.
+    fn is_superseded(&self, descriptor: &JobDescriptor) -> bool {
+        let key = (descriptor.repository.clone(), descriptor.branch.clone());
+        self.latest_for_key.lock().unwrap().get(&key) != Some(&descriptor.job_id)
+    }
+
+    /// Removes `job_id` from the queue-position bookkeeping once a worker picks it up.
+    fn dequeue(&self, job_id: i64) {
+        let mut queued_order = self.queued_order.lock().unwrap();
+        if let Some(index) = queued_order.iter().position(|id| *id == job_id) {
+            queued_order.remove(index);
+        }
+    }
+
+    fn set_phase(&self, job_id: i64, phase: JobPhase) {
+        self.phases.lock().unwrap().insert(job_id, phase);
+    }
+
+    /// Drops `job_id`'s live tracking once its terminal state has been persisted.
+    fn finish(&self, job_id: i64) {
+        self.phases.lock().unwrap().remove(&job_id);
+    }
+
+    /// A short human-readable summary of `job_id`'s current phase, including queue
+    /// position while it's still waiting for a worker.
+    fn phase_summary(&self, job_id: i64) -> Option<String> {
+        let phase_text = {
+            let phases = self.phases.lock().unwrap();
+            match phases.get(&job_id)? {
+                JobPhase::Queued => None,
+                other => Some(other.to_string()),
+            }
+        };
+
+        match phase_text {
+            Some(text) => Some(text),
+            None => {
+                let queued_order = self.queued_order.lock().unwrap();
+                let position = queued_order.iter().position(|id| *id == job_id)?;
+                Some(format!("Queued ({} of {})", position + 1, queued_order.len()))
+            }
+        }
+    }
+}
 
-    InProgress,
+/// A row of the `jobs` table, as rendered on the board.
+#[derive(Debug)]
+struct JobRow {
+    id: i64,
+    repository: String,
+    branch: String,
+    state: JobState,
+    run_output: Option<Output>,
+    test_output: Option<Output>,
+    error_message: Option<String>,
+    timeout_phase: Option<ExecutionPhase>,
 }
 
-impl Display for TestLogResult {
+impl Display for JobRow {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Success(o) => {
+        match self.state {
+            JobState::Pending => f.write_str("<span class='summary'>Queued</span>"),
+            JobState::Running => f.write_str("<span class='summary'>In Progress</span>"),
+            JobState::Success => {
                 f.write_str("<span class='summary'>Success</span><span>:</span><br />\n<div>\n")?;
-                Display::fmt(o, f)?;
+                if let Some(test_output) = &self.test_output {
+                    Display::fmt(test_output, f)?;
+                }
                 f.write_str("\n</div>")
             }
-            TestLogResult::SetupError(error) => {
-                f.write_str(
-                    "<span class='summary'>Setup Error</span><span>:</span><br />\n<div>\n",
-                )?;
-                Display::fmt(error, f)?;
-                f.write_str("</div>")
-            }
-            TestLogResult::TestError {
-                run_error_log,
-                test_error_log,
-            } => {
-                f.write_str(
-                    "<span class='summary'>Test Error</span><span>:</span><br />\n<div>\n",
-                )?;
-                if let Some(rel) = run_error_log {
-                    Display::fmt(rel, f)?;
-                    f.write_str("\n")?
+            JobState::Error => {
+                f.write_str("<span class='summary'>Error</span><span>:</span><br />\n<div>\n")?;
+                if let Some(run_output) = &self.run_output {
+                    Display::fmt(run_output, f)?;
+                    f.write_str("\n")?;
                 }
-                if let Some(tel) = test_error_log {
-                    Display::fmt(tel, f)?
+                if let Some(test_output) = &self.test_output {
+                    Display::fmt(test_output, f)?;
+                }
+                if let Some(error_message) = &self.error_message {
+                    f.write_str(error_message)?;
                 }
                 f.write_str("</div>")
             }
-            TestLogResult::InProgress => f.write_str("<span class='summary'>In Progress</span>"),
+            JobState::Timeout => {
+                let phase = self.timeout_phase.unwrap_or(ExecutionPhase::Run);
+                write!(
+                    f,
+                    "<span class='summary'>Timeout</span><span>:</span><br />\n<div>{} ran longer than the configured job timeout</div>",
+                    phase
+                )
+            }
+            JobState::Superseded => write!(
+                f,
+                "<span class='summary'>Superseded</span><span>:</span><br />\n<div>{}</div>",
+                self.error_message.as_deref().unwrap_or("Superseded by a newer push")
+            ),
         }
     }
 }
 
+/// Wraps the embedded SQLite database backing the `jobs` table, so that submissions and
+/// their results survive a server restart.
+struct DbCtx {
+    conn: Mutex<Connection>,
+}
+
+impl DbCtx {
+    fn open(db_path: &str) -> Result<Self, SetupError> {
+        let conn = Connection::open(db_path)?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS jobs (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                repository TEXT NOT NULL,
+                branch TEXT NOT NULL,
+                commit_sha TEXT NOT NULL,
+                state TEXT NOT NULL,
+                run_output TEXT,
+                test_output TEXT,
+                error_message TEXT,
+                timeout_phase TEXT
+            )",
+            [],
+        )?;
+
+        Ok(DbCtx {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn insert_pending(&self, repository: &str, branch: &str, commit_sha: &str) -> Result<i64, SetupError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO jobs (repository, branch, commit_sha, state) VALUES (?1, ?2, ?3, ?4)",
+            params![repository, branch, commit_sha, JobState::Pending.as_str()],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    fn mark_running(&self, id: i64) -> Result<(), SetupError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE jobs SET state = ?1 WHERE id = ?2",
+            params![JobState::Running.as_str(), id],
+        )?;
+        Ok(())
+    }
+
+    fn set_result(
+        &self,
+        id: i64,
+        state: JobState,
+        run_output: Option<&Output>,
+        test_output: Option<&Output>,
+        error_message: Option<&str>,
+        timeout_phase: Option<ExecutionPhase>,
+    ) -> Result<(), SetupError> {
+        let run_output = run_output.map(serde_json::to_string).transpose()?;
+        let test_output = test_output.map(serde_json::to_string).transpose()?;
+        let timeout_phase = timeout_phase.map(ExecutionPhase::as_str);
+
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE jobs
+             SET state = ?1, run_output = ?2, test_output = ?3, error_message = ?4, timeout_phase = ?5
+             WHERE id = ?6",
+            params![
+                state.as_str(),
+                run_output,
+                test_output,
+                error_message,
+                timeout_phase,
+                id
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn board(&self) -> Result<Vec<JobRow>, SetupError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, repository, branch, state, run_output, test_output, error_message, timeout_phase
+             FROM jobs ORDER BY id DESC",
+        )?;
+
+        let rows = stmt
+            .query_map([], |row| {
+                let run_output: Option<String> = row.get(4)?;
+                let test_output: Option<String> = row.get(5)?;
+                let timeout_phase: Option<String> = row.get(7)?;
+
+                Ok(JobRow {
+                    id: row.get(0)?,
+                    repository: row.get(1)?,
+                    branch: row.get(2)?,
+                    state: JobState::from_str(&row.get::<_, String>(3)?),
+                    run_output: run_output.map(|o| serde_json::from_str(&o).unwrap()),
+                    test_output: test_output.map(|o| serde_json::from_str(&o).unwrap()),
+                    error_message: row.get(6)?,
+                    timeout_phase: timeout_phase.map(|p| ExecutionPhase::from_str(&p)),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(rows)
+    }
+}
+
+/// Holds one `broadcast` channel per in-progress job, so `/board/{id}/stream` can forward
+/// live container output to any number of connected browsers.
+struct JobBroadcasts {
+    channels: Mutex<HashMap<i64, broadcast::Sender<String>>>,
+}
+
+impl JobBroadcasts {
+    fn new() -> Self {
+        JobBroadcasts {
+            channels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn register(&self, job_id: i64) -> broadcast::Sender<String> {
+        let (sender, _receiver) = broadcast::channel(1024);
+        self.channels.lock().unwrap().insert(job_id, sender.clone());
+        sender
+    }
+
+    fn unregister(&self, job_id: i64) {
+        self.channels.lock().unwrap().remove(&job_id);
+    }
+
+    fn subscribe(&self, job_id: i64) -> Option<broadcast::Receiver<String>> {
+        self.channels
+            .lock()
+            .unwrap()
+            .get(&job_id)
+            .map(broadcast::Sender::subscribe)
+    }
+}
+
 #[actix_rt::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let mut listen_fd = ListenFd::from_env();
@@ -120,21 +520,55 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let config: ConfigFile = ron::de::from_str(&config_content)?;
 
+    let docker = Docker::connect_with_local_defaults()?;
+    let db = DbCtx::open(&config.db_path)?;
+
+    println!("Loaded {} existing job(s) from {}", db.board()?.len(), config.db_path);
+
+    let job_timeout = Duration::from_secs(config.job_timeout_secs);
+    let max_concurrent_jobs = config.max_concurrent_jobs;
+    let tls = config.tls.clone();
+
     let conf_data = web::Data::new(config);
-    let result_data = web::Data::new(RwLock::new(Vec::<TestLogEntry>::new()));
+    let db_data = web::Data::new(db);
+    let docker_data = web::Data::new(docker);
+    let broadcasts_data = web::Data::new(JobBroadcasts::new());
+    let queue_data = JobQueue::new(
+        max_concurrent_jobs,
+        job_timeout,
+        docker_data.clone(),
+        db_data.clone(),
+        broadcasts_data.clone(),
+    );
+
+    recover_interrupted_jobs(&db_data, &conf_data, &queue_data)?;
 
     let mut server = HttpServer::new(move || {
         App::new()
             .app_data(conf_data.clone())
-            .app_data(result_data.clone())
+            .app_data(db_data.clone())
+            .app_data(docker_data.clone())
+            .app_data(broadcasts_data.clone())
+            .app_data(queue_data.clone())
             .service(web::resource("/").route(web::get().to(redirect_to_board)))
             .service(web::resource("/submission").route(web::post().to(submission_handler)))
             .service(web::resource("/board").route(web::get().to(redirect_to_board)))
             .service(web::resource("/board/").route(web::get().to(submission_lookup)))
             .service(web::resource("/board/style.css").route(web::get().to(style_handler)))
+            .service(web::resource("/board/{id}/stream").route(web::get().to(stream_handler)))
     });
 
-    server = if let Some(l) = listen_fd.take_tcp_listener(0)? {
+    server = if let Some(tls) = &tls {
+        let rustls_config = load_rustls_config(&tls.cert_path, &tls.key_path)?;
+        let sock_addresses: &[_] = &[
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 443)),
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 443)),
+        ];
+
+        println!("Starting HTTPS Server on {:?}", sock_addresses);
+
+        server.bind_rustls(sock_addresses, rustls_config)?
+    } else if let Some(l) = listen_fd.take_tcp_listener(0)? {
         println!("Starting Server using TCPListener from listen_fd.");
         server.listen(l)?
     } else {
@@ -147,16 +581,122 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
         server.bind(sock_addresses)?
     };
-    server.run().await?;
+
+    if tls.is_some() {
+        let redirect_sock_addresses: &[_] = &[
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 80)),
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 80)),
+        ];
+
+        println!(
+            "Starting HTTP\u{2192}HTTPS redirect Server on {:?}",
+            redirect_sock_addresses
+        );
+
+        let redirect_server = HttpServer::new(|| {
+            App::new().default_service(web::route().to(redirect_to_https))
+        })
+        .bind(redirect_sock_addresses)?
+        .run();
+
+        let (server_result, redirect_result) = tokio::join!(server.run(), redirect_server);
+        server_result?;
+        redirect_result?;
+    } else {
+        server.run().await?;
+    }
+
+    Ok(())
+}
+
+/// Sweeps rows a prior crash/restart left in `Pending` or `Running`, so they don't get
+/// stuck forever now that the `JobQueue` always starts empty. `Pending` jobs never started,
+/// so they're safe to re-enqueue into the fresh queue; `Running` jobs may have left a
+/// container behind from the old process, so they're marked `Error` instead of being
+/// resumed blindly.
+fn recover_interrupted_jobs(db: &DbCtx, conf: &ConfigFile, queue: &JobQueue) -> Result<(), SetupError> {
+    for job in db.board()? {
+        match job.state {
+            JobState::Running => {
+                db.set_result(
+                    job.id,
+                    JobState::Error,
+                    None,
+                    None,
+                    Some("Interrupted by a server restart"),
+                    None,
+                )?;
+                println!("Job {} was still running at the previous restart, marked as errored", job.id);
+            }
+            JobState::Pending => match conf.repos.iter().find(|rep| rep.match_url == job.repository) {
+                Some(rep) => {
+                    let clone_url = rep
+                        .clone_url
+                        .replace("{username}", &rep.deploy_user)
+                        .replace("{password}", &rep.deploy_token);
+
+                    queue.enqueue(JobDescriptor {
+                        job_id: job.id,
+                        repository: job.repository.clone(),
+                        branch: job.branch.clone(),
+                        clone_url,
+                        memory_limit_bytes: rep.memory_limit_bytes,
+                        nano_cpus: rep.nano_cpus,
+                    });
+                    println!("Re-queued pending job {} left over from the previous restart", job.id);
+                }
+                None => {
+                    db.set_result(
+                        job.id,
+                        JobState::Error,
+                        None,
+                        None,
+                        Some("Repository configuration was removed before this job could run"),
+                        None,
+                    )?;
+                }
+            },
+            _ => {}
+        }
+    }
 
     Ok(())
 }
 
+/// Loads `cert_path`/`key_path` (PEM, PKCS#8 private key) into a rustls `ServerConfig` for
+/// `HttpServer::bind_rustls`.
+fn load_rustls_config(cert_path: &str, key_path: &str) -> std::io::Result<RustlsServerConfig> {
+    let mut rustls_config = RustlsServerConfig::new(NoClientAuth::new());
+
+    let mut cert_file = std::io::BufReader::new(std::fs::File::open(cert_path)?);
+    let mut key_file = std::io::BufReader::new(std::fs::File::open(key_path)?);
+
+    let cert_chain = certs(&mut cert_file)
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid certificate"))?;
+    let mut keys = pkcs8_private_keys(&mut key_file)
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid private key"))?;
+
+    if keys.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "no PKCS#8 private key found",
+        ));
+    }
+
+    rustls_config
+        .set_single_cert(cert_chain, keys.remove(0))
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))?;
+
+    Ok(rustls_config)
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 struct RequestData {
     object_kind: String,
     #[serde(alias = "ref")]
     reference: String,
+    #[serde(alias = "checkout_sha")]
+    commit_sha: String,
     repository: Repo,
 }
 
@@ -176,36 +716,79 @@ async fn redirect_to_board() -> HttpResponse {
         .finish()
 }
 
-async fn submission_lookup(results: web::Data<RwLock<Vec<TestLogEntry>>>) -> HttpResponse {
-    let guard = results.read().unwrap();
+/// Used by the plaintext port-80 listener when TLS is enabled. Unlike `redirect_to_board`,
+/// this must point at an *absolute* `https://` URL - a relative `Location` would resolve
+/// back against the same plaintext origin and loop forever instead of reaching HTTPS.
+async fn redirect_to_https(req: HttpRequest) -> HttpResponse {
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("localhost");
 
-    let results: String = guard
+    HttpResponse::MovedPermanently()
+        .insert_header((header::LOCATION, format!("https://{}/board/", host)))
+        .finish()
+}
+
+async fn submission_lookup(
+    db: web::Data<DbCtx>,
+    queue: web::Data<JobQueue>,
+) -> Result<HttpResponse, actix_web::error::Error> {
+    let jobs = db
+        .board()
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?;
+
+    let results: String = jobs
         .iter()
-        .enumerate()
-        .rev()
-        .map(|(index, entry)| {
+        .map(|job| {
+            let phase_line = queue
+                .phase_summary(job.id)
+                .map(|phase| format!("<div class='summary'>{}</div>", phase))
+                .unwrap_or_default();
+
+            let live_view = match job.state {
+                JobState::Running => format!(
+                    "{phase_line}<pre id='submission{id}live'></pre>
+                    <script>
+                    (function() {{
+                        var container = document.getElementById('submission{id}live');
+                        var source = new EventSource('/board/{id}/stream');
+                        source.onmessage = function(event) {{ container.textContent += event.data + '\\n'; }};
+                        source.onerror = function() {{ source.close(); location.reload(); }};
+                    }})();
+                    </script>",
+                    phase_line = phase_line,
+                    id = job.id
+                ),
+                JobState::Pending => phase_line,
+                _ => String::new(),
+            };
+
             format!(
                 "
             <tr>
-                <td><a id='submission{index}' href='#submission{index}'>{index}</a></td>
+                <td><a id='submission{id}' href='#submission{id}'>{id}</a></td>
                 <td>{repo}</td>
                 <td>{branch}</td>
                 <td>
-                    <input id='submission{index}result' class='visToggle' type='checkbox'>
-                    <label for='submission{index}result' class='show'>[Show]</label>
-                    <label for='submission{index}result' class='hide'>[Hide]</label>
+                    <input id='submission{id}result' class='visToggle' type='checkbox'>
+                    <label for='submission{id}result' class='show'>[Show]</label>
+                    <label for='submission{id}result' class='hide'>[Hide]</label>
                     <div>{result}</div>
+                    {live_view}
                 </td>
             </tr>",
-                index = index,
-                repo = &entry.repository,
-                branch = &entry.branch,
-                result = &entry.result
+                id = job.id,
+                repo = &job.repository,
+                branch = &job.branch,
+                result = job,
+                live_view = live_view
             )
         })
         .collect();
 
-    HttpResponse::Ok().body(format!(
+    Ok(HttpResponse::Ok().body(format!(
         "\
 <html>
     <head>
@@ -222,42 +805,128 @@ async fn submission_lookup(results: web::Data<RwLock<Vec<TestLogEntry>>>) -> Htt
 </html>
 ",
         results
-    ))
+    )))
+}
+
+/// Forwards a job's live container output as Server-Sent Events until the job finishes
+/// and its broadcast channel is torn down.
+async fn stream_handler(path: web::Path<i64>, broadcasts: web::Data<JobBroadcasts>) -> HttpResponse {
+    let job_id = path.into_inner();
+
+    let receiver = match broadcasts.subscribe(job_id) {
+        Some(receiver) => receiver,
+        None => return HttpResponse::NotFound().body("No live output for this job"),
+    };
+
+    let events = BroadcastStream::new(receiver).filter_map(|chunk| async move {
+        match chunk {
+            Ok(line) => Some(Ok::<_, actix_web::Error>(web::Bytes::from(format!(
+                "data: {}\n\n",
+                line.replace('\n', "\ndata: ")
+            )))),
+            Err(_) => None,
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .streaming(events)
 }
 
 async fn submission_handler(
-    form: web::Json<RequestData>,
+    req: HttpRequest,
+    body: web::Bytes,
     conf: web::Data<ConfigFile>,
-    results: web::Data<RwLock<Vec<TestLogEntry>>>,
+    db: web::Data<DbCtx>,
+    queue: web::Data<JobQueue>,
 ) -> Result<HttpResponse, actix_web::error::Error> {
-    println!("{:?}", form);
+    let form: RequestData = match serde_json::from_slice(&body) {
+        Ok(form) => form,
+        Err(_) => return Ok(HttpResponse::BadRequest().body("Malformed payload")),
+    };
 
-    for rep in conf.repos.iter() {
-        let branch = form.reference.replace("refs/heads/", "");
+    println!("{:?}", form);
 
-        if branch != "submission" && branch != "master" && !branch.starts_with("submissions/") {
-            return Ok(HttpResponse::Ok().body("Skipping none master|submission branch"));
+    // Identifying which repo's secret to check requires reading `git_http_url`, but
+    // everything past this point (branch filtering, queueing) must wait until that
+    // repo's signature has actually verified - otherwise an unauthenticated caller
+    // could use the responses below as an oracle for repo/branch configuration.
+    let rep = match conf
+        .repos
+        .iter()
+        .find(|rep| form.repository.git_http_url == rep.match_url)
+    {
+        Some(rep) => rep,
+        None => {
+            return Ok(HttpResponse::Ok().body(format!(
+                "Unknown Repository {}",
+                form.repository.git_http_url
+            )))
         }
+    };
 
-        if form.repository.git_http_url == rep.match_url {
-            let clone_url = rep
-                .clone_url
-                .replace("{username}", &rep.deploy_user)
-                .replace("{password}", &rep.deploy_token);
-            let branch_clone = branch.clone();
-            let match_clone = rep.match_url.clone();
-            actix_rt::Arbiter::current().spawn_fn(move || {
-                test_wrapper(&match_clone, &clone_url, &branch_clone, results.clone())
-            });
+    if !verify_webhook_signature(
+        req.headers().get("X-Hub-Signature-256"),
+        &body,
+        &rep.webhook_secret,
+    ) {
+        return Ok(HttpResponse::Unauthorized().body("Invalid webhook signature"));
+    }
 
-            return Ok(HttpResponse::Ok().body("Running Test!"));
-        }
+    let branch = form.reference.replace("refs/heads/", "");
+
+    if branch != "submission" && branch != "master" && !branch.starts_with("submissions/") {
+        return Ok(HttpResponse::Ok().body("Skipping none master|submission branch"));
     }
 
-    Ok(HttpResponse::Ok().body(format!(
-        "Unknown Repository {}",
-        form.repository.git_http_url
-    )))
+    let job_id = db
+        .insert_pending(&rep.match_url, &branch, &form.commit_sha)
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?;
+
+    let clone_url = rep
+        .clone_url
+        .replace("{username}", &rep.deploy_user)
+        .replace("{password}", &rep.deploy_token);
+
+    queue.enqueue(JobDescriptor {
+        job_id,
+        repository: rep.match_url.clone(),
+        branch,
+        clone_url,
+        memory_limit_bytes: rep.memory_limit_bytes,
+        nano_cpus: rep.nano_cpus,
+    });
+
+    Ok(HttpResponse::Ok().body(format!("Queued Test! (job {})", job_id)))
+}
+
+/// Checks `X-Hub-Signature-256` (`sha256=<hex>`) against `HMAC-SHA256(secret, body)`,
+/// comparing digests in constant time.
+fn verify_webhook_signature(
+    signature_header: Option<&header::HeaderValue>,
+    body: &[u8],
+    secret: &str,
+) -> bool {
+    let signature_hex = match signature_header
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("sha256="))
+    {
+        Some(signature_hex) => signature_hex,
+        None => return false,
+    };
+
+    let signature = match hex::decode(signature_hex) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    let mut mac = match Hmac::<Sha256>::new_from_slice(secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.update(body);
+
+    mac.verify_slice(&signature).is_ok()
 }
 
 macro_rules! impl_from_for {
@@ -277,12 +946,18 @@ enum SetupError {
     RonError(ron::Error),
     Utf8Error(std::string::FromUtf8Error),
     ContainerBuildFailed(Output),
+    DockerError(bollard::errors::Error),
+    DbError(rusqlite::Error),
+    SerdeJsonError(serde_json::Error),
 }
 
 impl_from_for!(git2::Error => SetupError as GitError);
 impl_from_for!(std::io::Error => SetupError as IOError);
 impl_from_for!(ron::Error => SetupError as RonError);
 impl_from_for!(std::string::FromUtf8Error => SetupError as Utf8Error);
+impl_from_for!(bollard::errors::Error => SetupError as DockerError);
+impl_from_for!(rusqlite::Error => SetupError as DbError);
+impl_from_for!(serde_json::Error => SetupError as SerdeJsonError);
 
 impl Error for SetupError {}
 
@@ -294,64 +969,117 @@ impl Display for SetupError {
             SetupError::RonError(ron_err) => Display::fmt(ron_err, f),
             SetupError::Utf8Error(utf8_error) => Display::fmt(utf8_error, f),
             SetupError::ContainerBuildFailed(cbf) => Display::fmt(cbf, f),
+            SetupError::DockerError(docker_err) => Display::fmt(docker_err, f),
+            SetupError::DbError(db_err) => Display::fmt(db_err, f),
+            SetupError::SerdeJsonError(serde_err) => Display::fmt(serde_err, f),
         }
     }
 }
 
-fn test_wrapper(
-    match_url: &str,
-    clone_url: &str,
-    branch: &str,
-    results: web::Data<RwLock<Vec<TestLogEntry>>>,
+/// Picked up by a worker task: drops `descriptor` if a newer push already superseded it,
+/// otherwise hands it to `test_wrapper`.
+async fn run_queued_job(
+    queue: &web::Data<JobQueue>,
+    docker: &web::Data<Docker>,
+    db: &web::Data<DbCtx>,
+    broadcasts: &web::Data<JobBroadcasts>,
+    descriptor: JobDescriptor,
+    job_timeout: Duration,
 ) {
-    let index = {
-        let mut guard = results.write().unwrap();
-        let len = guard.len();
-        guard.push(TestLogEntry {
-            repository: match_url.into(),
-            branch: branch.into(),
-            result: TestLogResult::InProgress,
-        });
-        len
-    };
+    let job_id = descriptor.job_id;
+    queue.dequeue(job_id);
 
-    match test(clone_url, branch) {
-        Ok(result) => {
-            results.write().unwrap().get_mut(index).map(|e| {
-                e.result = match result {
-                    TestResult::Success { test } => TestLogResult::Success(test),
-                    TestResult::TestError { test } => TestLogResult::TestError {
-                        test_error_log: Some(test),
-                        run_error_log: None,
-                    },
-
-                    TestResult::RunError { run } => TestLogResult::TestError {
-                        test_error_log: None,
-                        run_error_log: Some(run),
-                    },
-                    TestResult::RunTestError { run, test } => TestLogResult::TestError {
-                        test_error_log: Some(test),
-                        run_error_log: Some(run),
-                    },
-                }
-            });
+    if queue.is_superseded(&descriptor) {
+        println!("Job {} superseded by a newer push, skipping", job_id);
+        queue.finish(job_id);
+        if let Err(error) = db.set_result(
+            job_id,
+            JobState::Superseded,
+            None,
+            None,
+            Some("Superseded by a newer push"),
+            None,
+        ) {
+            eprintln!("Failed to mark job {} as superseded: {}", job_id, error);
+        }
+        return;
+    }
+
+    test_wrapper(queue, docker, db, broadcasts, descriptor, job_timeout).await;
+}
+
+async fn test_wrapper(
+    queue: &web::Data<JobQueue>,
+    docker: &web::Data<Docker>,
+    db: &web::Data<DbCtx>,
+    broadcasts: &web::Data<JobBroadcasts>,
+    descriptor: JobDescriptor,
+    job_timeout: Duration,
+) {
+    let job_id = descriptor.job_id;
+
+    if let Err(error) = db.mark_running(job_id) {
+        eprintln!("Failed to mark job {} as running: {}", job_id, error);
+    }
+
+    let sender = broadcasts.register(job_id);
+
+    let result = test(queue, docker, &descriptor, job_timeout, sender).await;
+
+    let persisted = match &result {
+        Ok(TestResult::Success { test }) => {
+            db.set_result(job_id, JobState::Success, None, Some(test), None, None)
+        }
+        Ok(TestResult::RunError { run }) => {
+            db.set_result(job_id, JobState::Error, Some(run), None, None, None)
+        }
+        Ok(TestResult::TestError { test }) => {
+            db.set_result(job_id, JobState::Error, None, Some(test), None, None)
+        }
+        Ok(TestResult::RunTestError { run, test }) => {
+            db.set_result(job_id, JobState::Error, Some(run), Some(test), None, None)
+        }
+        Ok(TestResult::Timeout { phase }) => {
+            db.set_result(job_id, JobState::Timeout, None, None, None, Some(*phase))
         }
         Err(error) => {
-            results
-                .write()
-                .unwrap()
-                .get_mut(index)
-                .map(|e| e.result = TestLogResult::SetupError(error));
+            let message = error.to_string();
+            db.set_result(job_id, JobState::Error, None, None, Some(&message), None)
         }
+    };
+
+    if let Err(error) = persisted {
+        eprintln!("Failed to persist result of job {}: {}", job_id, error);
     }
+
+    match result {
+        Ok(test_result) => queue.set_phase(job_id, JobPhase::Done(test_result)),
+        Err(error) => queue.set_phase(job_id, JobPhase::Failed(error.to_string())),
+    }
+
+    queue.finish(job_id);
+    broadcasts.unregister(job_id);
 }
 
-fn test(clone_url: &str, branch: &str) -> Result<TestResult, SetupError> {
+async fn test(
+    queue: &web::Data<JobQueue>,
+    docker: &Docker,
+    descriptor: &JobDescriptor,
+    job_timeout: Duration,
+    sender: broadcast::Sender<String>,
+) -> Result<TestResult, SetupError> {
+    let job_id = descriptor.job_id;
+    let branch = &descriptor.branch;
+    let memory_limit_bytes = descriptor.memory_limit_bytes;
+    let nano_cpus = descriptor.nano_cpus;
+
+    queue.set_phase(job_id, JobPhase::Cloning);
+
     let tmp_dir = Builder::new().suffix("submission").tempdir()?;
 
     let mut repo_builder = RepoBuilder::new();
 
-    let _repo = repo_builder.branch(branch).clone(clone_url, tmp_dir.path())?;
+    let _repo = repo_builder.branch(branch).clone(&descriptor.clone_url, tmp_dir.path())?;
 
     println!("Cloned");
     println!("Checked out {} branch!", branch);
@@ -377,102 +1105,297 @@ fn test(clone_url: &str, branch: &str) -> Result<TestResult, SetupError> {
 
     println!("Copied Dockerfile");
 
-    // setup container
-    let out = std::process::Command::new("docker")
-        .arg("build")
-        .arg("--rm")
-        .arg("--quiet")
-        .arg("--network=none")
-        .arg(tmp_dir.path())
-        .output()?;
+    queue.set_phase(job_id, JobPhase::Building);
 
-    if !out.status.success() {
-        return Err(SetupError::ContainerBuildFailed(Output {
-            stdout: String::from_utf8(out.stdout)?,
-            stderr: String::from_utf8(out.stderr)?,
-        }));
-    }
+    let image_id = build_image(docker, tmp_dir.path()).await?;
 
     tmp_dir.close()?;
 
-    let id = { String::from_utf8(out.stdout)?.trim().to_string() };
+    println!("Container build with Image Id {}!", image_id);
 
-    println!("Container build with Image Id {}!", id);
+    let host_config = HostConfig {
+        memory: Some(memory_limit_bytes),
+        nano_cpus: Some(nano_cpus),
+        network_mode: Some("none".to_string()),
+        ..Default::default()
+    };
 
     let server = "localhost";
     let player = "player";
 
     // run run.sh
-    let result = std::process::Command::new("docker")
-        .arg("run")
-        .arg("--rm")
-        .arg(&id)
-        .arg(server)
-        .arg(player)
-        .output()?;
-
-    // run test.sh
-    let test_result = std::process::Command::new("docker")
-        .arg("run")
-        .arg("--rm")
-        .arg("--entrypoint")
-        .arg("./test.sh")
-        .arg(&id)
-        .output()?;
-
-    let del_res = std::process::Command::new("docker")
-        .arg("rmi")
-        .arg(&id)
-        .output()?;
-
-    if del_res.status.success() {
-        println!("Deleted Container Image!");
+    queue.set_phase(job_id, JobPhase::Running);
+    let run_result = run_container(
+        docker,
+        &image_id,
+        Some(vec![server.to_string(), player.to_string()]),
+        None,
+        host_config.clone(),
+        job_timeout,
+        sender.clone(),
+    )
+    .await;
+
+    // run test.sh, but only if run.sh actually produced an outcome to test
+    queue.set_phase(job_id, JobPhase::Testing);
+    let test_result = match run_result {
+        Ok(ref _outcome) => {
+            Some(
+                run_container(
+                    docker,
+                    &image_id,
+                    None,
+                    Some(vec!["./test.sh".to_string()]),
+                    host_config,
+                    job_timeout,
+                    sender,
+                )
+                .await,
+            )
+        }
+        Err(_) => None,
+    };
+
+    if let Err(remove_err) = docker
+        .remove_image(&image_id, Some(RemoveImageOptions::default()), None)
+        .await
+    {
+        eprintln!("Failed to delete Image! {}", remove_err);
     } else {
-        eprintln!("Failed to delete Image!");
-        println!("{}", String::from_utf8(del_res.stdout)?);
-        eprintln!("{}", String::from_utf8(del_res.stderr)?);
+        println!("Deleted Container Image!");
     }
 
-    match (result.status.success(), test_result.status.success()) {
-        (true, true) => {
-            println!("Success");
-            Ok(TestResult::Success {
-                test: Output {
-                    stdout: String::from_utf8(test_result.stdout)?,
-                    stderr: String::from_utf8(test_result.stderr)?,
-                },
+    let run_outcome = run_result?;
+    let test_outcome = test_result.unwrap()?;
+
+    match (run_outcome, test_outcome) {
+        (ContainerOutcome::TimedOut, _) => {
+            println!("Run timed out!");
+            Ok(TestResult::Timeout {
+                phase: ExecutionPhase::Run,
             })
         }
-        (false, false) => {
-            println!("Run and Test failed!");
-            Ok(TestResult::RunTestError {
-                run: Output {
-                    stdout: String::from_utf8(result.stdout)?,
-                    stderr: String::from_utf8(result.stderr)?,
-                },
-                test: Output {
-                    stdout: String::from_utf8(test_result.stdout)?,
-                    stderr: String::from_utf8(test_result.stderr)?,
-                },
+        (_, ContainerOutcome::TimedOut) => {
+            println!("Test timed out!");
+            Ok(TestResult::Timeout {
+                phase: ExecutionPhase::Test,
             })
         }
-        (false, _) => {
+        (
+            ContainerOutcome::Exited { success: true, .. },
+            ContainerOutcome::Exited {
+                success: true,
+                output: test,
+            },
+        ) => {
+            println!("Success");
+            Ok(TestResult::Success { test })
+        }
+        (
+            ContainerOutcome::Exited {
+                success: false,
+                output: run,
+            },
+            ContainerOutcome::Exited {
+                success: false,
+                output: test,
+            },
+        ) => {
+            println!("Run and Test failed!");
+            Ok(TestResult::RunTestError { run, test })
+        }
+        (
+            ContainerOutcome::Exited {
+                success: false,
+                output: run,
+            },
+            _,
+        ) => {
             println!("Run failed!");
-            Ok(TestResult::RunError {
-                run: Output {
-                    stdout: String::from_utf8(result.stdout)?,
-                    stderr: String::from_utf8(result.stderr)?,
-                },
-            })
+            Ok(TestResult::RunError { run })
         }
-        (_, false) => {
+        (
+            _,
+            ContainerOutcome::Exited {
+                success: false,
+                output: test,
+            },
+        ) => {
             println!("Test failed!");
-            Ok(TestResult::TestError {
-                test: Output {
-                    stdout: String::from_utf8(test_result.stdout)?,
-                    stderr: String::from_utf8(test_result.stderr)?,
-                },
-            })
+            Ok(TestResult::TestError { test })
         }
     }
 }
+
+/// Builds the Dockerfile staged into `context_dir` via the Docker Engine API and returns
+/// the resulting image id.
+async fn build_image(docker: &Docker, context_dir: &Path) -> Result<String, SetupError> {
+    let mut tar_builder = tar::Builder::new(Vec::new());
+    tar_builder.append_dir_all(".", context_dir)?;
+    let context_tar = tar_builder.into_inner()?;
+
+    let options = BuildImageOptions {
+        dockerfile: "Dockerfile",
+        rm: true,
+        networkmode: "none",
+        q: true,
+        ..Default::default()
+    };
+
+    let mut build_stream = docker.build_image(options, None, Some(context_tar.into()));
+
+    let mut image_id = None;
+    let mut build_log = String::new();
+
+    while let Some(chunk) = build_stream.next().await {
+        let info = chunk?;
+
+        if let Some(stream) = info.stream {
+            build_log.push_str(&stream);
+        }
+
+        if let Some(error) = info.error {
+            build_log.push_str(&error);
+            return Err(SetupError::ContainerBuildFailed(Output {
+                stdout: build_log,
+                stderr: String::new(),
+            }));
+        }
+
+        if let Some(aux) = info.aux {
+            image_id = aux.id;
+        }
+    }
+
+    image_id.ok_or_else(|| {
+        SetupError::ContainerBuildFailed(Output {
+            stdout: build_log,
+            stderr: "docker did not report an image id for the build".to_string(),
+        })
+    })
+}
+
+/// The terminal state of a single `docker run`-equivalent container.
+enum ContainerOutcome {
+    Exited { success: bool, output: Output },
+    TimedOut,
+}
+
+/// Creates and runs a container from `image`, waiting up to `timeout` for it to finish
+/// while forwarding its output to `sender` as it's produced.
+/// On timeout the container is stopped and removed and `ContainerOutcome::TimedOut` is
+/// returned instead of its (incomplete) output.
+async fn run_container(
+    docker: &Docker,
+    image: &str,
+    cmd: Option<Vec<String>>,
+    entrypoint: Option<Vec<String>>,
+    host_config: HostConfig,
+    timeout: Duration,
+    sender: broadcast::Sender<String>,
+) -> Result<ContainerOutcome, SetupError> {
+    let config = Config {
+        image: Some(image.to_string()),
+        cmd,
+        entrypoint,
+        host_config: Some(host_config),
+        ..Default::default()
+    };
+
+    let container = docker.create_container::<String, String>(None, config).await?;
+
+    if let Err(err) = docker.start_container::<String>(&container.id, None).await {
+        stop_and_remove_container(docker, &container.id).await;
+        return Err(err.into());
+    }
+
+    let log_task = actix_rt::spawn(stream_container_logs(
+        docker.clone(),
+        container.id.clone(),
+        sender,
+    ));
+
+    let mut wait_stream = docker.wait_container::<String>(&container.id, None);
+
+    let success = match tokio::time::timeout(timeout, wait_stream.next()).await {
+        Ok(Some(Ok(response))) => response.status_code == 0,
+        Ok(Some(Err(err))) => {
+            log_task.abort();
+            stop_and_remove_container(docker, &container.id).await;
+            return Err(err.into());
+        }
+        Ok(None) => false,
+        Err(_) => {
+            log_task.abort();
+            stop_and_remove_container(docker, &container.id).await;
+            return Ok(ContainerOutcome::TimedOut);
+        }
+    };
+
+    let output = log_task.await;
+
+    stop_and_remove_container(docker, &container.id).await;
+
+    let output = output
+        .map_err(|join_err| SetupError::ContainerBuildFailed(Output {
+            stdout: String::new(),
+            stderr: join_err.to_string(),
+        }))??;
+
+    Ok(ContainerOutcome::Exited { success, output })
+}
+
+/// Best-effort stop + force-remove, shared by every `run_container` exit path so a
+/// container is never left behind regardless of how the wait for it ended.
+async fn stop_and_remove_container(docker: &Docker, container_id: &str) {
+    let _ = docker.stop_container(container_id, None).await;
+    let _ = docker
+        .remove_container(
+            container_id,
+            Some(RemoveContainerOptions {
+                force: true,
+                ..Default::default()
+            }),
+        )
+        .await;
+}
+
+/// Follows a container's stdout/stderr, broadcasting each chunk to `sender` as it arrives
+/// and accumulating it into the final `Output` returned once the stream ends.
+async fn stream_container_logs(
+    docker: Docker,
+    container_id: String,
+    sender: broadcast::Sender<String>,
+) -> Result<Output, SetupError> {
+    let options = LogsOptions::<String> {
+        follow: true,
+        stdout: true,
+        stderr: true,
+        ..Default::default()
+    };
+
+    let mut log_stream = docker.logs(&container_id, Some(options));
+    let mut stdout = String::new();
+    let mut stderr = String::new();
+
+    while let Some(chunk) = log_stream.next().await {
+        let line = match chunk? {
+            LogOutput::StdOut { message } => {
+                let line = String::from_utf8_lossy(&message).into_owned();
+                stdout.push_str(&line);
+                line
+            }
+            LogOutput::StdErr { message } => {
+                let line = String::from_utf8_lossy(&message).into_owned();
+                stderr.push_str(&line);
+                line
+            }
+            _ => continue,
+        };
+
+        // No one may be listening yet (or any more) - the output is still kept for the board.
+        let _ = sender.send(line);
+    }
+
+    Ok(Output { stdout, stderr })
+}